@@ -1,14 +1,52 @@
 use std::fs::read_to_string;
 use std::io;
+use std::os::unix::fs::FileExt;
 use std::path::PathBuf;
 
 pub const RAPL_BASE_PATH: &str = "/sys/class/powercap/";
 
+/// Source of cumulative RAPL energy readings for a single domain.
+///
+/// Abstracting over this lets both the powercap sysfs interface and the raw MSR interface
+/// feed the same delta/power math in `IntelRapl::read_power`.
+trait EnergySource {
+    /// Reads the current cumulative energy counter, in microjoules.
+    fn read_energy_uj(&self) -> Result<u64, io::Error>;
+    /// The counter's wraparound range, in microjoules.
+    fn max_energy_range_uj(&self) -> u64;
+}
+
+struct SysfsEnergySource {
+    path: PathBuf,
+    max_energy_range_uj: u64,
+}
+
+impl EnergySource for SysfsEnergySource {
+    fn read_energy_uj(&self) -> Result<u64, io::Error> {
+        let energy_uj_string = read_to_string(self.path.join("energy_uj"))?;
+        Ok(energy_uj_string.trim().parse::<u64>().unwrap())
+    }
+
+    fn max_energy_range_uj(&self) -> u64 {
+        self.max_energy_range_uj
+    }
+}
+
 pub struct IntelRapl {
     pub name: String,
-    path: PathBuf,
+    /// The raw contents of the zone's `name` file, e.g. "package-0", "core", or "dram".
+    pub domain_name: String,
+    /// The top-level package/socket number this zone belongs to (the first segment of its
+    /// `intel-rapl:N[:M...]` directory name).
+    pub zone_id: String,
+    /// The full nested zone id (e.g. "0:1") if this is a subzone (pp0/pp1/dram/uncore);
+    /// `None` for a top-level package zone.
+    pub subzone_id: Option<String>,
+    /// The zone's sysfs directory, if this entry is backed by the powercap driver. `None`
+    /// for MSR-backed entries, which don't expose power-capping controls.
+    path: Option<PathBuf>,
+    source: Box<dyn EnergySource>,
     last_energy: u64,
-    max_energy_range_uj: u64,
     last_time: std::time::Instant,
     cumulative_energy_uj: u64,
     cumulative_energy_start_time: std::time::Instant,
@@ -19,21 +57,30 @@ pub struct IntelRapl {
 ///
 /// # Fields
 /// - `name`: The name of the RAPL device.
-/// - `path`: The filesystem path to the RAPL device.
+/// - `domain_name`: The raw contents of the zone's `name` file.
+/// - `zone_id`: The top-level package/socket number this zone belongs to.
+/// - `subzone_id`: The full nested zone id (e.g. "0:1") if this is a subzone, else `None`.
+/// - `path`: The filesystem path to the RAPL device, if backed by the powercap driver.
+/// - `source`: The `EnergySource` (sysfs or MSR) this entry reads energy from.
 /// - `last_energy`: The last read energy value in microjoules.
-/// - `max_energy_range_uj`: The maximum energy range in microjoules.
 /// - `last_time`: The timestamp of the last energy reading.
 /// - `cumulative_energy_uj`: The cumulative energy consumed since initialization, in microjoules.
 /// - `cumulative_energy_start_time`: The timestamp when cumulative energy measurement started.
 ///
 /// # Methods
 /// - `new(path: PathBuf) -> Result<Self, io::Error>`: Constructs a new `IntelRapl` instance from the given path, reading initial device information.
+/// - `from_msr(cpu: u32, domain: MsrDomain) -> Result<Self, io::Error>`: Constructs an `IntelRapl` instance backed by the raw MSR interface.
 /// - `read_name(path: &std::path::Path) -> Result<String, io::Error>`: Reads the device name from the specified path.
 /// - `read_max_energy_range_uj(path: &std::path::Path) -> Result<u64, io::Error>`: Reads the maximum energy range (in microjoules) from the specified path.
 /// - `read_energy(&self) -> Result<(u64, std::time::Instant), io::Error>`: Reads the current energy value and timestamp from the device.
 /// - `read_power(&mut self) -> Result<f64, io::Error>`: Calculates and returns the instantaneous power usage in watts, updating internal state.
 /// - `average_power(&self) -> f64`: Returns the average power usage in watts since cumulative measurement started.
 /// - `cumulative_energy_wh(&self) -> f64`: Returns the cumulative energy consumed in watt-hours since cumulative measurement started.
+/// - `read_constraints(&self) -> Result<Vec<Constraint>, io::Error>`: Reads the power-capping constraints (`constraint_N_*`) exposed by this zone.
+/// - `set_power_limit(&self, index: u32, microwatts: u64) -> Result<(), io::Error>`: Writes `constraint_N_power_limit_uw`.
+/// - `set_time_window(&self, index: u32, micros: u64) -> Result<(), io::Error>`: Writes `constraint_N_time_window_us`.
+/// - `enabled(&self) -> Result<bool, io::Error>`: Reads whether the zone's power cap is currently enforced.
+/// - `set_enabled(&self, enabled: bool) -> Result<(), io::Error>`: Toggles whether the zone's power cap is enforced.
 impl IntelRapl {
     fn new(path: PathBuf) -> Result<Self, io::Error> {
         let name_value = Self::read_name(&path)?;
@@ -42,14 +89,18 @@ impl IntelRapl {
             .and_then(|os_str| os_str.to_str())
             .unwrap_or_default();
         let name = format!("{}/{}", parent_dir_name, name_value);
+        let (zone_id, subzone_id) = Self::parse_zone_id(parent_dir_name);
 
         let max_energy_range_uj = Self::read_max_energy_range_uj(&path)?;
         let now = std::time::Instant::now();
         Ok(Self {
             name,
-            path,
+            domain_name: name_value,
+            zone_id,
+            subzone_id,
+            path: Some(path.clone()),
+            source: Box::new(SysfsEnergySource { path, max_energy_range_uj }),
             last_energy: 0,
-            max_energy_range_uj,
             last_time: now,
             cumulative_energy_uj: 0,
             cumulative_energy_start_time: now,
@@ -57,6 +108,50 @@ impl IntelRapl {
         })
     }
 
+    /// Constructs an `IntelRapl` entry backed by the raw MSR interface (`/dev/cpu/N/msr`),
+    /// for machines where the powercap driver isn't loaded (common in VMs and older kernels).
+    ///
+    /// `MsrDomain::Package` is recorded as the package-level entry (`subzone_id: None`), like
+    /// a sysfs `intel-rapl:N` zone; the other domains are recorded as synthetic subzones of
+    /// that package (`subzone_id: Some("N:pp0")` etc.) so `aggregate_by_socket` can tell the
+    /// whole-package reading apart from its component planes and avoid double-counting them.
+    ///
+    /// Fails if the MSR device can't be opened/read or if `domain` isn't implemented by this
+    /// CPU (e.g. no DRAM plane).
+    pub fn from_msr(cpu: u32, domain: MsrDomain) -> Result<Self, io::Error> {
+        let source = MsrEnergySource::new(cpu, domain)?;
+        source.read_energy_uj()?; // validates the domain is actually readable on this CPU
+        let name = format!("msr:cpu{}/{}", cpu, domain.name());
+        let subzone_id = match domain {
+            MsrDomain::Package => None,
+            _ => Some(format!("{}:{}", cpu, domain.name())),
+        };
+        let now = std::time::Instant::now();
+        Ok(Self {
+            name,
+            domain_name: domain.name().to_string(),
+            zone_id: cpu.to_string(),
+            subzone_id,
+            path: None,
+            source: Box::new(source),
+            last_energy: 0,
+            last_time: now,
+            cumulative_energy_uj: 0,
+            cumulative_energy_start_time: now,
+            max_power: 0.0,
+        })
+    }
+
+    /// Splits a directory name like `intel-rapl:0` or `intel-rapl:0:1` into its top-level
+    /// `zone_id` ("0") and, for nested subzones, the full `subzone_id` ("0:1").
+    fn parse_zone_id(dir_name: &str) -> (String, Option<String>) {
+        let full_id = dir_name.strip_prefix("intel-rapl:").unwrap_or(dir_name);
+        match full_id.split_once(':') {
+            Some((zone, _rest)) => (zone.to_string(), Some(full_id.to_string())),
+            None => (full_id.to_string(), None),
+        }
+    }
+
     fn read_name(path: &std::path::Path) -> Result<String, io::Error> {
         let rapl_name = read_to_string(path.join("name"))?;
         Ok(rapl_name.trim().into())
@@ -68,25 +163,25 @@ impl IntelRapl {
     }
 
     fn read_energy(&self) -> Result<(u64, std::time::Instant), io::Error> {
-        let energy_uj_string = read_to_string(self.path.join("energy_uj"))?;
-        let energy_uj = energy_uj_string.trim().parse::<u64>().unwrap();
+        let energy_uj = self.source.read_energy_uj()?;
         Ok((energy_uj, std::time::Instant::now()))
     }
 
     pub fn read_power(&mut self) -> Result<f64, io::Error> {
         let (energy_uj, updated_time) = self.read_energy()?;
-        if energy_uj > self.max_energy_range_uj {
-            return Err(io::Error::new(io::ErrorKind::Other, "energy_uj value out of range"));
+        let max_energy_range_uj = self.source.max_energy_range_uj();
+        if energy_uj > max_energy_range_uj {
+            return Err(io::Error::other("energy_uj value out of range"));
         }
 
-        let delta_energy =  if self.last_energy <= 0 {
+        let delta_energy =  if self.last_energy == 0 {
             self.cumulative_energy_start_time = updated_time;
             0u64
         } else {
             if energy_uj >= self.last_energy {
                 energy_uj - self.last_energy
             } else {
-                energy_uj + (self.max_energy_range_uj - self.last_energy)
+                energy_uj + (max_energy_range_uj - self.last_energy)
             }
         };
 
@@ -115,9 +210,237 @@ impl IntelRapl {
     pub fn max_power(&self) -> f64 {
         self.max_power
     }
+
+    /// Returns this entry's sysfs directory, or an error if it's MSR-backed and therefore
+    /// doesn't expose the powercap control files.
+    fn sysfs_path(&self) -> Result<&PathBuf, io::Error> {
+        self.path.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "power-capping controls require the powercap sysfs interface, not available via MSR",
+            )
+        })
+    }
+
+    fn constraint_file(&self, index: u32, suffix: &str) -> Result<PathBuf, io::Error> {
+        Ok(self.sysfs_path()?.join(format!("constraint_{}_{}", index, suffix)))
+    }
+
+    /// Reads the power-capping constraints (`constraint_N_*`) exposed by this zone.
+    ///
+    /// Constraints are numbered from 0 and stop at the first missing `constraint_N_name` file,
+    /// matching the sysfs convention (commonly "long_term"/"short_term" for package zones).
+    pub fn read_constraints(&self) -> Result<Vec<Constraint>, io::Error> {
+        let mut constraints = Vec::new();
+        let mut index = 0;
+        loop {
+            let name_path = self.constraint_file(index, "name")?;
+            if !name_path.exists() {
+                break;
+            }
+
+            let name = read_to_string(&name_path)?.trim().to_string();
+            let power_limit_uw = read_to_string(self.constraint_file(index, "power_limit_uw")?)?
+                .trim()
+                .parse::<u64>()
+                .unwrap();
+            let time_window_us = read_to_string(self.constraint_file(index, "time_window_us")?)?
+                .trim()
+                .parse::<u64>()
+                .unwrap();
+            let max_power_uw = read_to_string(self.constraint_file(index, "max_power_uw")?)
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok());
+
+            constraints.push(Constraint {
+                index,
+                name,
+                power_limit_uw,
+                time_window_us,
+                max_power_uw,
+            });
+            index += 1;
+        }
+        Ok(constraints)
+    }
+
+    /// Sets `constraint_N_power_limit_uw` to the given value, in microwatts.
+    ///
+    /// Requires root; a `PermissionDenied` error is returned (not a panic) if the process
+    /// lacks permission to write the sysfs file.
+    pub fn set_power_limit(&self, index: u32, microwatts: u64) -> Result<(), io::Error> {
+        self.write_constraint_value(index, "power_limit_uw", microwatts)
+    }
+
+    /// Sets `constraint_N_time_window_us` to the given value, in microseconds.
+    ///
+    /// Requires root; a `PermissionDenied` error is returned (not a panic) if the process
+    /// lacks permission to write the sysfs file.
+    pub fn set_time_window(&self, index: u32, micros: u64) -> Result<(), io::Error> {
+        self.write_constraint_value(index, "time_window_us", micros)
+    }
+
+    fn write_constraint_value(&self, index: u32, suffix: &str, value: u64) -> Result<(), io::Error> {
+        std::fs::write(self.constraint_file(index, suffix)?, value.to_string()).map_err(|e| {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("permission denied writing constraint_{}_{} (are you root?)", index, suffix),
+                )
+            } else {
+                e
+            }
+        })
+    }
+
+    /// Returns whether this zone's power cap is currently enforced (the `enabled` file).
+    pub fn enabled(&self) -> Result<bool, io::Error> {
+        let value = read_to_string(self.sysfs_path()?.join("enabled"))?;
+        Ok(value.trim() == "1")
+    }
+
+    /// Enables or disables enforcement of this zone's power cap.
+    ///
+    /// Requires root; a `PermissionDenied` error is returned (not a panic) if the process
+    /// lacks permission to write the sysfs file.
+    pub fn set_enabled(&self, enabled: bool) -> Result<(), io::Error> {
+        std::fs::write(self.sysfs_path()?.join("enabled"), if enabled { "1" } else { "0" }).map_err(|e| {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "permission denied writing enabled (are you root?)",
+                )
+            } else {
+                e
+            }
+        })
+    }
+}
+
+/// A single RAPL power-capping constraint (`constraint_N_*` in sysfs), e.g. the
+/// "long_term" or "short_term" limit on a package or dram zone.
+pub struct Constraint {
+    /// The constraint index (the `N` in `constraint_N_*`).
+    pub index: u32,
+    /// The constraint's name, e.g. "long_term" or "short_term".
+    pub name: String,
+    /// The configured power limit, in microwatts.
+    pub power_limit_uw: u64,
+    /// The averaging time window for this limit, in microseconds.
+    pub time_window_us: u64,
+    /// The hardware-enforced maximum power limit, in microwatts, if exposed.
+    pub max_power_uw: Option<u64>,
 }
 
 
+/// The MSR address of `MSR_RAPL_POWER_UNIT`, which reports the units the energy-status MSRs
+/// below are expressed in.
+const MSR_RAPL_POWER_UNIT: u64 = 0x606;
+
+/// A RAPL power plane reachable through the raw MSR interface.
+#[derive(Clone, Copy)]
+pub enum MsrDomain {
+    /// The whole CPU package (`MSR_PKG_ENERGY_STATUS`, 0x611).
+    Package,
+    /// The core power plane (`MSR_PP0_ENERGY_STATUS`, 0x639).
+    Pp0,
+    /// The graphics power plane (`MSR_PP1_ENERGY_STATUS`, 0x641).
+    Pp1,
+    /// The DRAM power plane (`MSR_DRAM_ENERGY_STATUS`, 0x619).
+    Dram,
+}
+
+impl MsrDomain {
+    fn energy_status_msr(self) -> u64 {
+        match self {
+            MsrDomain::Package => 0x611,
+            MsrDomain::Pp0 => 0x639,
+            MsrDomain::Pp1 => 0x641,
+            MsrDomain::Dram => 0x619,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            MsrDomain::Package => "package",
+            MsrDomain::Pp0 => "pp0",
+            MsrDomain::Pp1 => "pp1",
+            MsrDomain::Dram => "dram",
+        }
+    }
+}
+
+/// Reads cumulative energy for one RAPL domain straight from `/dev/cpu/N/msr`, for systems
+/// where the powercap driver isn't loaded.
+struct MsrEnergySource {
+    file: std::fs::File,
+    energy_status_msr: u64,
+    energy_unit_uj_per_count: f64,
+    max_energy_range_uj: u64,
+}
+
+impl MsrEnergySource {
+    fn new(cpu: u32, domain: MsrDomain) -> Result<Self, io::Error> {
+        let file = std::fs::File::open(format!("/dev/cpu/{}/msr", cpu))?;
+        let power_unit = Self::read_msr(&file, MSR_RAPL_POWER_UNIT)?;
+        let energy_unit_uj_per_count = energy_unit_uj_per_count(power_unit);
+        let max_energy_range_uj = (u32::MAX as f64) * energy_unit_uj_per_count;
+
+        Ok(Self {
+            file,
+            energy_status_msr: domain.energy_status_msr(),
+            energy_unit_uj_per_count,
+            max_energy_range_uj: max_energy_range_uj as u64,
+        })
+    }
+
+    /// Reads one 64-bit MSR. `/dev/cpu/N/msr` is addressed by the MSR number itself as the
+    /// byte offset (the kernel driver does `reg = *ppos; rdmsr_safe_on_cpu(cpu, reg, ...)`),
+    /// not `msr * 8`.
+    fn read_msr(file: &std::fs::File, msr: u64) -> Result<u64, io::Error> {
+        let mut buf = [0u8; 8];
+        file.read_exact_at(&mut buf, msr)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+}
+
+/// Converts the raw `MSR_RAPL_POWER_UNIT` value into microjoules per energy-status count.
+///
+/// Energy Status Units live in bits 12:8 of the MSR: energy is reported in units of
+/// 1/2^ESU joules.
+fn energy_unit_uj_per_count(power_unit_msr: u64) -> f64 {
+    let energy_status_units = (power_unit_msr >> 8) & 0x1f;
+    1e6 / (1u64 << energy_status_units) as f64
+}
+
+impl EnergySource for MsrEnergySource {
+    fn read_energy_uj(&self) -> Result<u64, io::Error> {
+        let raw = Self::read_msr(&self.file, self.energy_status_msr)?;
+        let counter = raw & 0xffff_ffff; // the energy-status counter is 32 bits wide
+        Ok((counter as f64 * self.energy_unit_uj_per_count) as u64)
+    }
+
+    fn max_energy_range_uj(&self) -> u64 {
+        self.max_energy_range_uj
+    }
+}
+
+/// Discovers RAPL domains through the MSR interface on the given CPUs, for use when the
+/// powercap sysfs tree (`init_intel_rapl_entries`) isn't available.
+///
+/// Pass one CPU number per package to monitor (e.g. the first CPU of each socket); the MSR
+/// interface has no equivalent of sysfs's directory tree to discover sockets on its own.
+///
+/// Domains not implemented by a given CPU (e.g. no DRAM plane on some desktop parts) are
+/// silently skipped rather than treated as an error.
+pub fn init_msr_entries(cpus: &[u32]) -> Result<Vec<IntelRapl>, io::Error> {
+    let domains = [MsrDomain::Package, MsrDomain::Pp0, MsrDomain::Pp1, MsrDomain::Dram];
+    Ok(cpus
+        .iter()
+        .flat_map(|&cpu| domains.iter().filter_map(move |&domain| IntelRapl::from_msr(cpu, domain).ok()))
+        .collect())
+}
+
 /// Initializes and returns a vector of `IntelRapl` entries found under the specified base path.
 ///
 /// # Arguments
@@ -125,18 +448,278 @@ impl IntelRapl {
 ///
 /// # Returns
 /// * `Result<Vec<IntelRapl>, io::Error>` - A vector of initialized `IntelRapl` devices, or an error if initialization fails.
+///
+/// Discovery recurses into each `intel-rapl:N` package zone to also pick up its nested
+/// subzones (`intel-rapl:N:0`, `intel-rapl:N:1`, ...), which represent the core (pp0),
+/// graphics (pp1), dram, and uncore power planes. Each package zone is listed immediately
+/// before its subzones.
 pub fn init_intel_rapl_entries(base_path: &std::path::Path) -> Result<Vec<IntelRapl>, io::Error> {
-    std::fs::read_dir(base_path).unwrap()
-        .map(|res| res.unwrap().path())
-        .filter(|path| {
-            let name_file = path.join("name");
-            let energy_file = path.join("energy_uj");
-
-            path.is_dir() &&
-                path.file_name().unwrap().to_str().unwrap().starts_with("intel-rapl:") &&
-                name_file.exists() &&
-                energy_file.exists()
+    let mut entries = Vec::new();
+    collect_intel_rapl_entries(base_path, &mut entries)?;
+    Ok(entries)
+}
+
+fn is_rapl_zone_dir(path: &std::path::Path) -> bool {
+    path.is_dir() &&
+        path.file_name().unwrap().to_str().unwrap().starts_with("intel-rapl:") &&
+        path.join("name").exists() &&
+        path.join("energy_uj").exists()
+}
+
+/// Returns whether `pattern` identifies `entry`, matching against its `domain_name` (e.g.
+/// "dram") or its id (`zone_id` like "0", or `subzone_id` like "0:1").
+pub fn matches_domain(entry: &IntelRapl, pattern: &str) -> bool {
+    pattern == entry.domain_name
+        || pattern == entry.zone_id
+        || entry.subzone_id.as_deref() == Some(pattern)
+}
+
+/// Keeps only the entries matching `include`/`exclude`, which are compared against both a
+/// zone's `domain_name` (e.g. "dram") and its id (`zone_id` like "0", or `subzone_id` like
+/// "0:1"). An empty `include` list keeps everything; `exclude` is applied afterwards and
+/// always wins on conflict.
+///
+/// Mirrors cc-metric-collector's `exclude_device_by_id` / `exclude_device_by_name` config model.
+pub fn filter_entries(entries: Vec<IntelRapl>, include: &[String], exclude: &[String]) -> Vec<IntelRapl> {
+    entries
+        .into_iter()
+        .filter(|entry| {
+            let included = include.is_empty() || include.iter().any(|pattern| matches_domain(entry, pattern));
+            let excluded = exclude.iter().any(|pattern| matches_domain(entry, pattern));
+            included && !excluded
         })
-        .map(IntelRapl::new)
-        .collect::<Result<Vec<_>,_>>()
+        .collect()
+}
+
+/// How `aggregate_by_socket` rolls multiple `IntelRapl` zones up into per-socket and grand
+/// totals, inspired by scaphandre's `Topology` abstraction.
+///
+/// A package zone and its subzones (pp0/pp1/dram/uncore) measure overlapping power, so only
+/// one layer of the hierarchy may be summed at a time.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    /// Sum only the top-level package zones, ignoring any subzones.
+    PackagesOnly,
+    /// Sum only leaf zones: a package's subzones where present, else the package itself.
+    LeavesOnly,
+}
+
+/// One domain's reading for a single tick, paired with the entry it came from so
+/// `aggregate_by_socket` can group by socket without re-reading energy itself.
+pub struct Reading<'a> {
+    pub entry: &'a IntelRapl,
+    pub power_w: f64,
+}
+
+/// A summed power/energy total over one or more `IntelRapl` zones.
+pub struct Totals {
+    /// The socket (`zone_id`) this total covers, or "total" for the grand total.
+    pub label: String,
+    pub power_w: f64,
+    pub energy_wh: f64,
+    pub avg_power_w: f64,
+}
+
+/// Groups `readings` by socket (`zone_id`) and sums instantaneous power, cumulative Wh, and
+/// average power within each socket, then returns the per-socket subtotals alongside a
+/// machine-wide grand total.
+///
+/// `aggregation` selects which layer of the package/subzone hierarchy is summed, to avoid
+/// double-counting a package zone against its own pp0/pp1/dram/uncore subzones.
+pub fn aggregate_by_socket(readings: &[Reading], aggregation: Aggregation) -> (Vec<Totals>, Totals) {
+    let has_subzone =
+        |zone_id: &str| readings.iter().any(|r| r.entry.zone_id == zone_id && r.entry.subzone_id.is_some());
+
+    let is_selected = |r: &&Reading| match aggregation {
+        Aggregation::PackagesOnly => r.entry.subzone_id.is_none(),
+        Aggregation::LeavesOnly => r.entry.subzone_id.is_some() || !has_subzone(&r.entry.zone_id),
+    };
+
+    let mut sockets: Vec<Totals> = Vec::new();
+    for reading in readings.iter().filter(is_selected) {
+        match sockets.iter_mut().find(|socket| socket.label == reading.entry.zone_id) {
+            Some(socket) => {
+                socket.power_w += reading.power_w;
+                socket.energy_wh += reading.entry.cumulative_energy_wh();
+                socket.avg_power_w += reading.entry.average_power();
+            }
+            None => sockets.push(Totals {
+                label: reading.entry.zone_id.clone(),
+                power_w: reading.power_w,
+                energy_wh: reading.entry.cumulative_energy_wh(),
+                avg_power_w: reading.entry.average_power(),
+            }),
+        }
+    }
+
+    let total = Totals {
+        label: "total".to_string(),
+        power_w: sockets.iter().map(|s| s.power_w).sum(),
+        energy_wh: sockets.iter().map(|s| s.energy_wh).sum(),
+        avg_power_w: sockets.iter().map(|s| s.avg_power_w).sum(),
+    };
+
+    (sockets, total)
+}
+
+fn collect_intel_rapl_entries(dir: &std::path::Path, entries: &mut Vec<IntelRapl>) -> Result<(), io::Error> {
+    for res in std::fs::read_dir(dir)? {
+        let path = res?.path();
+        if !is_rapl_zone_dir(&path) {
+            continue;
+        }
+
+        let subdir = path.clone();
+        entries.push(IntelRapl::new(path)?);
+        collect_intel_rapl_entries(&subdir, entries)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct ConstantEnergySource;
+
+    impl EnergySource for ConstantEnergySource {
+        fn read_energy_uj(&self) -> Result<u64, io::Error> {
+            Ok(0)
+        }
+
+        fn max_energy_range_uj(&self) -> u64 {
+            u64::MAX
+        }
+    }
+
+    /// Builds an `IntelRapl` with no backing file, fixing `cumulative_energy_uj` and the
+    /// elapsed time so `cumulative_energy_wh`/`average_power` are deterministic.
+    fn test_entry(
+        zone_id: &str,
+        subzone_id: Option<&str>,
+        domain_name: &str,
+        cumulative_energy_uj: u64,
+        elapsed: std::time::Duration,
+    ) -> IntelRapl {
+        let now = std::time::Instant::now();
+        IntelRapl {
+            name: format!("intel-rapl:{}/{}", zone_id, domain_name),
+            domain_name: domain_name.to_string(),
+            zone_id: zone_id.to_string(),
+            subzone_id: subzone_id.map(|s| s.to_string()),
+            path: None,
+            source: Box::new(ConstantEnergySource),
+            last_energy: 0,
+            last_time: now,
+            cumulative_energy_uj,
+            cumulative_energy_start_time: now - elapsed,
+            max_power: 0.0,
+        }
+    }
+
+    #[test]
+    fn parse_zone_id_top_level() {
+        assert_eq!(IntelRapl::parse_zone_id("intel-rapl:0"), ("0".to_string(), None));
+    }
+
+    #[test]
+    fn parse_zone_id_subzone() {
+        assert_eq!(
+            IntelRapl::parse_zone_id("intel-rapl:0:1"),
+            ("0".to_string(), Some("0:1".to_string()))
+        );
+    }
+
+    #[test]
+    fn matches_domain_matches_name_and_ids() {
+        let entry = test_entry("0", Some("0:1"), "dram", 0, std::time::Duration::from_secs(1));
+        assert!(matches_domain(&entry, "dram"));
+        assert!(matches_domain(&entry, "0"));
+        assert!(matches_domain(&entry, "0:1"));
+        assert!(!matches_domain(&entry, "core"));
+    }
+
+    #[test]
+    fn filter_entries_applies_include_then_exclude() {
+        let entries = vec![
+            test_entry("0", None, "package-0", 0, std::time::Duration::from_secs(1)),
+            test_entry("0", Some("0:1"), "dram", 0, std::time::Duration::from_secs(1)),
+            test_entry("1", None, "package-1", 0, std::time::Duration::from_secs(1)),
+        ];
+
+        let filtered = filter_entries(entries, &["dram".to_string(), "1".to_string()], &["1".to_string()]);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].domain_name, "dram");
+    }
+
+    #[test]
+    fn energy_unit_uj_per_count_decodes_esu_bits() {
+        // ESU = 16 (bits 12:8 of 0x1000) => 1e6 / 2^16 uJ per count.
+        let uj_per_count = energy_unit_uj_per_count(0x1000);
+        assert!((uj_per_count - 1e6 / 65536.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn aggregate_by_socket_packages_only_ignores_subzones() {
+        let socket0_pkg = test_entry("0", None, "package-0", 3_600_000_000, std::time::Duration::from_secs(3600));
+        let socket0_dram = test_entry("0", Some("0:1"), "dram", 360_000_000, std::time::Duration::from_secs(3600));
+        let socket1_pkg = test_entry("1", None, "package-1", 7_200_000_000, std::time::Duration::from_secs(3600));
+
+        let readings = vec![
+            Reading { entry: &socket0_pkg, power_w: 10.0 },
+            Reading { entry: &socket0_dram, power_w: 2.0 },
+            Reading { entry: &socket1_pkg, power_w: 20.0 },
+        ];
+
+        let (sockets, total) = aggregate_by_socket(&readings, Aggregation::PackagesOnly);
+
+        assert_eq!(sockets.len(), 2);
+        let socket0 = sockets.iter().find(|s| s.label == "0").unwrap();
+        assert_eq!(socket0.power_w, 10.0); // must not also include the dram subzone's 2.0 W
+        assert_eq!(total.power_w, 30.0);
+    }
+
+    #[test]
+    fn aggregate_by_socket_leaves_only_prefers_subzones_but_falls_back_to_package() {
+        let socket0_pkg = test_entry("0", None, "package-0", 0, std::time::Duration::from_secs(1));
+        let socket0_pp0 = test_entry("0", Some("0:0"), "core", 0, std::time::Duration::from_secs(1));
+        let socket0_dram = test_entry("0", Some("0:1"), "dram", 0, std::time::Duration::from_secs(1));
+        let socket1_pkg = test_entry("1", None, "package-1", 0, std::time::Duration::from_secs(1));
+
+        let readings = vec![
+            Reading { entry: &socket0_pkg, power_w: 10.0 },
+            Reading { entry: &socket0_pp0, power_w: 4.0 },
+            Reading { entry: &socket0_dram, power_w: 2.0 },
+            Reading { entry: &socket1_pkg, power_w: 20.0 },
+        ];
+
+        let (sockets, total) = aggregate_by_socket(&readings, Aggregation::LeavesOnly);
+
+        let socket0 = sockets.iter().find(|s| s.label == "0").unwrap();
+        assert_eq!(socket0.power_w, 6.0); // pp0 + dram, excluding the overlapping package total
+        let socket1 = sockets.iter().find(|s| s.label == "1").unwrap();
+        assert_eq!(socket1.power_w, 20.0); // no subzones, so the package itself is the leaf
+        assert_eq!(total.power_w, 26.0);
+    }
+
+    #[test]
+    fn msr_package_and_plane_domains_are_distinguished_for_aggregation() {
+        // Regression test: MSR-backed Package/Pp0/Pp1/Dram readings used to all share
+        // `subzone_id: None`, which made aggregate_by_socket double- (or quadruple-) count them.
+        let package = test_entry("0", None, "package", 0, std::time::Duration::from_secs(1));
+        let pp0 = test_entry("0", Some("0:pp0"), "pp0", 0, std::time::Duration::from_secs(1));
+        let dram = test_entry("0", Some("0:dram"), "dram", 0, std::time::Duration::from_secs(1));
+
+        let readings = vec![
+            Reading { entry: &package, power_w: 10.0 },
+            Reading { entry: &pp0, power_w: 4.0 },
+            Reading { entry: &dram, power_w: 2.0 },
+        ];
+
+        let (_, total) = aggregate_by_socket(&readings, Aggregation::PackagesOnly);
+        assert_eq!(total.power_w, 10.0);
+
+        let (_, total) = aggregate_by_socket(&readings, Aggregation::LeavesOnly);
+        assert_eq!(total.power_w, 6.0);
+    }
 }
\ No newline at end of file
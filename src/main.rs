@@ -4,38 +4,427 @@ use intel_rapl::{RAPL_BASE_PATH, init_intel_rapl_entries};
 
 const UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let base_path = std::path::Path::new(RAPL_BASE_PATH);
-    if !base_path.exists() {
-        let err_msg = format!("{} not found", base_path.display());
-        return Err(err_msg.into())
+/// Output mode selected via `--format`.
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// The default in-place ANSI table, redrawn every tick.
+    Table,
+    /// One CSV row per domain per tick, suitable for piping into a file.
+    Csv,
+    /// One JSON object per domain per tick (JSON Lines), suitable for a metrics pipeline.
+    Json,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!("unknown --format '{}' (expected table, csv, or json)", other)),
+        }
+    }
+}
+
+fn parse_aggregate(value: &str) -> Result<Option<intel_rapl::Aggregation>, String> {
+    match value {
+        "off" => Ok(None),
+        "packages" => Ok(Some(intel_rapl::Aggregation::PackagesOnly)),
+        "leaves" => Ok(Some(intel_rapl::Aggregation::LeavesOnly)),
+        other => Err(format!("unknown --aggregate '{}' (expected off, packages, or leaves)", other)),
     }
+}
 
-    let mut entries = init_intel_rapl_entries(base_path)?;
+struct Args {
+    format: OutputFormat,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    base_path: String,
+    aggregate: Option<intel_rapl::Aggregation>,
+    power_limits: Vec<(String, u32, u64)>,
+    time_windows: Vec<(String, u32, u64)>,
+    set_enabled: Vec<(String, bool)>,
+    show_constraints: bool,
+    msr_cpus: Vec<u32>,
+}
 
-    if entries.len() == 0 {
-        eprintln!("Error: No intel-rapl domains found");
-        return Err("No intel-rapl domains found".into());
+/// Splits a `--include`/`--exclude` value like "dram,package-0" into its comma-separated parts.
+fn parse_domain_list(value: &str) -> Vec<String> {
+    value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+/// Parses a `--msr-cpus` value like "0,8" into the CPU numbers whose MSRs should be read —
+/// one per package to monitor, since the MSR interface can't discover sockets on its own.
+fn parse_cpu_list(value: &str) -> Result<Vec<u32>, String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<u32>().map_err(|_| format!("invalid CPU number '{}' in --msr-cpus", s)))
+        .collect()
+}
+
+/// Parses a `--set-power-limit`/`--set-time-window` value of the form `ZONE:INDEX=VALUE`,
+/// e.g. "package-0:0=15000000".
+fn parse_constraint_assignment(value: &str) -> Result<(String, u32, u64), String> {
+    let (zone_index, amount) =
+        value.split_once('=').ok_or_else(|| format!("expected ZONE:INDEX=VALUE, got '{}'", value))?;
+    // rsplit_once, not split_once: zone ids can themselves contain a colon (subzone ids are
+    // "<zone>:<subzone>", e.g. "0:1"), so the index is always the last colon-separated part.
+    let (zone, index) =
+        zone_index.rsplit_once(':').ok_or_else(|| format!("expected ZONE:INDEX=VALUE, got '{}'", value))?;
+    let index = index.parse::<u32>().map_err(|_| format!("invalid constraint index '{}'", index))?;
+    let amount = amount.parse::<u64>().map_err(|_| format!("invalid value '{}'", amount))?;
+    Ok((zone.to_string(), index, amount))
+}
+
+/// Parses a `--set-enabled` value of the form `ZONE=on|off`.
+fn parse_enabled_assignment(value: &str) -> Result<(String, bool), String> {
+    let (zone, state) = value.split_once('=').ok_or_else(|| format!("expected ZONE=on|off, got '{}'", value))?;
+    let enabled = match state {
+        "on" => true,
+        "off" => false,
+        other => return Err(format!("expected 'on' or 'off' in --set-enabled, got '{}'", other)),
+    };
+    Ok((zone.to_string(), enabled))
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut format = OutputFormat::Table;
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    let mut base_path = RAPL_BASE_PATH.to_string();
+    let mut aggregate = None;
+    let mut power_limits = Vec::new();
+    let mut time_windows = Vec::new();
+    let mut set_enabled = Vec::new();
+    let mut show_constraints = false;
+    let mut msr_cpus = vec![0];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let value = args.next().ok_or("--format requires a value")?;
+                format = OutputFormat::parse(&value)?;
+            }
+            "--include" => {
+                let value = args.next().ok_or("--include requires a value")?;
+                include.extend(parse_domain_list(&value));
+            }
+            "--exclude" => {
+                let value = args.next().ok_or("--exclude requires a value")?;
+                exclude.extend(parse_domain_list(&value));
+            }
+            "--base-path" => {
+                base_path = args.next().ok_or("--base-path requires a value")?;
+            }
+            "--aggregate" => {
+                let value = args.next().ok_or("--aggregate requires a value")?;
+                aggregate = parse_aggregate(&value)?;
+            }
+            "--set-power-limit" => {
+                let value = args.next().ok_or("--set-power-limit requires a value")?;
+                power_limits.push(parse_constraint_assignment(&value)?);
+            }
+            "--set-time-window" => {
+                let value = args.next().ok_or("--set-time-window requires a value")?;
+                time_windows.push(parse_constraint_assignment(&value)?);
+            }
+            "--set-enabled" => {
+                let value = args.next().ok_or("--set-enabled requires a value")?;
+                set_enabled.push(parse_enabled_assignment(&value)?);
+            }
+            "--show-constraints" => {
+                show_constraints = true;
+            }
+            "--msr-cpus" => {
+                let value = args.next().ok_or("--msr-cpus requires a value")?;
+                msr_cpus = parse_cpu_list(&value)?;
+                if msr_cpus.is_empty() {
+                    return Err("--msr-cpus requires at least one CPU number".to_string());
+                }
+            }
+            other => return Err(format!("unknown argument '{}'", other)),
+        }
     }
+    Ok(Args {
+        format,
+        include,
+        exclude,
+        base_path,
+        aggregate,
+        power_limits,
+        time_windows,
+        set_enabled,
+        show_constraints,
+        msr_cpus,
+    })
+}
 
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Escapes a domain name for use as a JSON string value.
+fn json_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Reads power for every entry, returning one `Some(power_w)` per entry (in the same order),
+/// or `None` where the read failed for that tick.
+fn read_all_power(entries: &mut [intel_rapl::IntelRapl]) -> Vec<Option<f64>> {
+    entries.iter_mut().map(|entry| entry.read_power().ok()).collect()
+}
+
+/// Builds the `Reading`s needed for `intel_rapl::aggregate_by_socket` from this tick's powers.
+fn readings_for<'a>(entries: &'a [intel_rapl::IntelRapl], powers: &[Option<f64>]) -> Vec<intel_rapl::Reading<'a>> {
+    entries
+        .iter()
+        .zip(powers.iter())
+        .filter_map(|(entry, power)| power.map(|power_w| intel_rapl::Reading { entry, power_w }))
+        .collect()
+}
+
+fn run_table(entries: &mut [intel_rapl::IntelRapl], aggregate: Option<intel_rapl::Aggregation>) -> ! {
     loop {
         // Print table header
         println!("{:<28} {:>10} {:>12} {:>12} {:>12}", "Domain", "Power (W)", "Energy (Wh)", "Avg Pwr (W)", "Max Pwr (W)");
         println!("{:-<80}", ""); // <-- increased to 80 dashes
 
+        let powers = read_all_power(entries);
         let mut printed_line = 0;
-        for entry in &mut entries {
-            if let Ok(power) = entry.read_power() {
+        for (entry, power) in entries.iter().zip(powers.iter()) {
+            if let Some(power) = power {
                 println!("{:<28} {:>10.3} {:>12.3} {:>12.3} {:>12.3}", entry.name, power, entry.cumulative_energy_wh(), entry.average_power(), entry.max_power());
                 printed_line += 1;
             }
         }
 
+        if let Some(aggregation) = aggregate {
+            let readings = readings_for(entries, &powers);
+            let (sockets, total) = intel_rapl::aggregate_by_socket(&readings, aggregation);
+            for socket in &sockets {
+                println!(
+                    "{:<28} {:>10.3} {:>12.3} {:>12.3} {:>12}",
+                    format!("package-{} total", socket.label), socket.power_w, socket.energy_wh, socket.avg_power_w, "-"
+                );
+                printed_line += 1;
+            }
+            println!("{:<28} {:>10.3} {:>12.3} {:>12.3} {:>12}", "Total", total.power_w, total.energy_wh, total.avg_power_w, "-");
+            printed_line += 1;
+        }
+
         sleep(UPDATE_INTERVAL);
 
         // Move cursor up to overwrite previous output
         let cursor_up = "\x1b[A".repeat(printed_line + 2); // +2 for header and separator
         print!("{}\r", cursor_up);
     }
+}
+
+fn run_csv(entries: &mut [intel_rapl::IntelRapl], aggregate: Option<intel_rapl::Aggregation>) -> ! {
+    println!("timestamp,domain,power_w,energy_wh,avg_power_w,max_power_w");
+    loop {
+        let timestamp = unix_timestamp();
+        let powers = read_all_power(entries);
+        for (entry, power) in entries.iter().zip(powers.iter()) {
+            if let Some(power) = power {
+                println!(
+                    "{},{},{:.3},{:.3},{:.3},{:.3}",
+                    timestamp, entry.name, power, entry.cumulative_energy_wh(), entry.average_power(), entry.max_power()
+                );
+            }
+        }
+
+        if let Some(aggregation) = aggregate {
+            let readings = readings_for(entries, &powers);
+            let (sockets, total) = intel_rapl::aggregate_by_socket(&readings, aggregation);
+            for socket in &sockets {
+                println!(
+                    "{},package-{} total,{:.3},{:.3},{:.3},",
+                    timestamp, socket.label, socket.power_w, socket.energy_wh, socket.avg_power_w
+                );
+            }
+            println!("{},total,{:.3},{:.3},{:.3},", timestamp, total.power_w, total.energy_wh, total.avg_power_w);
+        }
+
+        sleep(UPDATE_INTERVAL);
+    }
+}
+
+fn run_json(entries: &mut [intel_rapl::IntelRapl], aggregate: Option<intel_rapl::Aggregation>) -> ! {
+    loop {
+        let timestamp = unix_timestamp();
+        let powers = read_all_power(entries);
+        for (entry, power) in entries.iter().zip(powers.iter()) {
+            if let Some(power) = power {
+                println!(
+                    "{{\"timestamp\":{},\"domain\":\"{}\",\"power_w\":{:.3},\"energy_wh\":{:.3},\"avg_power_w\":{:.3},\"max_power_w\":{:.3}}}",
+                    timestamp, json_escape(&entry.name), power, entry.cumulative_energy_wh(), entry.average_power(), entry.max_power()
+                );
+            }
+        }
+
+        if let Some(aggregation) = aggregate {
+            let readings = readings_for(entries, &powers);
+            let (sockets, total) = intel_rapl::aggregate_by_socket(&readings, aggregation);
+            for socket in &sockets {
+                println!(
+                    "{{\"timestamp\":{},\"domain\":\"package-{} total\",\"power_w\":{:.3},\"energy_wh\":{:.3},\"avg_power_w\":{:.3}}}",
+                    timestamp, socket.label, socket.power_w, socket.energy_wh, socket.avg_power_w
+                );
+            }
+            println!(
+                "{{\"timestamp\":{},\"domain\":\"total\",\"power_w\":{:.3},\"energy_wh\":{:.3},\"avg_power_w\":{:.3}}}",
+                timestamp, total.power_w, total.energy_wh, total.avg_power_w
+            );
+        }
+
+        sleep(UPDATE_INTERVAL);
+    }
+}
+
+/// Finds the first entry matching `zone` (by `domain_name`, `zone_id`, or `subzone_id`);
+/// any later entries that also match are ignored.
+fn find_entry<'a>(
+    entries: &'a [intel_rapl::IntelRapl],
+    zone: &str,
+) -> Result<&'a intel_rapl::IntelRapl, Box<dyn std::error::Error>> {
+    entries
+        .iter()
+        .find(|entry| intel_rapl::matches_domain(entry, zone))
+        .ok_or_else(|| format!("no domain matching '{}'", zone).into())
+}
+
+/// Applies the power-capping writes requested via `--set-power-limit`, `--set-time-window`,
+/// and `--set-enabled`, so the tool can act as a RAPL controller rather than just a monitor.
+fn apply_constraint_writes(entries: &[intel_rapl::IntelRapl], args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    for (zone, index, microwatts) in &args.power_limits {
+        find_entry(entries, zone)?.set_power_limit(*index, *microwatts)?;
+        eprintln!("{}: set constraint {} power limit to {} uW", zone, index, microwatts);
+    }
+    for (zone, index, micros) in &args.time_windows {
+        find_entry(entries, zone)?.set_time_window(*index, *micros)?;
+        eprintln!("{}: set constraint {} time window to {} us", zone, index, micros);
+    }
+    for (zone, enabled) in &args.set_enabled {
+        find_entry(entries, zone)?.set_enabled(*enabled)?;
+        eprintln!("{}: set enabled={}", zone, enabled);
+    }
+    Ok(())
+}
+
+/// Prints each entry's power-capping constraints and enabled state for `--show-constraints`.
+fn print_constraints(entries: &[intel_rapl::IntelRapl]) {
+    for entry in entries {
+        match entry.read_constraints() {
+            Ok(constraints) => {
+                let enabled = entry.enabled().map(|e| e.to_string()).unwrap_or_else(|e| format!("unknown ({})", e));
+                println!("{} (enabled={})", entry.name, enabled);
+                for constraint in &constraints {
+                    println!(
+                        "  [{}] {}: power_limit={}uW time_window={}us max_power={:?}uW",
+                        constraint.index, constraint.name, constraint.power_limit_uw, constraint.time_window_us, constraint.max_power_uw
+                    );
+                }
+            }
+            Err(e) => eprintln!("{}: failed to read constraints: {}", entry.name, e),
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = parse_args().map_err(|e| -> Box<dyn std::error::Error> { e.into() })?;
 
+    let base_path = std::path::Path::new(&args.base_path);
+    let entries = if base_path.exists() {
+        init_intel_rapl_entries(base_path)?
+    } else {
+        eprintln!(
+            "{} not found, falling back to the MSR interface for cpu(s) {:?} (pass --msr-cpus to cover other sockets)",
+            base_path.display(),
+            args.msr_cpus
+        );
+        intel_rapl::init_msr_entries(&args.msr_cpus)?
+    };
+    let mut entries = intel_rapl::filter_entries(entries, &args.include, &args.exclude);
+
+    if entries.is_empty() {
+        eprintln!("Error: No intel-rapl domains found");
+        return Err("No intel-rapl domains found".into());
+    }
+
+    apply_constraint_writes(&entries, &args)?;
+    if args.show_constraints {
+        print_constraints(&entries);
+    }
+
+    match args.format {
+        OutputFormat::Table => run_table(&mut entries, args.aggregate),
+        OutputFormat::Csv => run_csv(&mut entries, args.aggregate),
+        OutputFormat::Json => run_json(&mut entries, args.aggregate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_format_parse_accepts_known_values() {
+        assert!(OutputFormat::parse("table").unwrap() == OutputFormat::Table);
+        assert!(OutputFormat::parse("csv").unwrap() == OutputFormat::Csv);
+        assert!(OutputFormat::parse("json").unwrap() == OutputFormat::Json);
+    }
+
+    #[test]
+    fn output_format_parse_rejects_unknown_value() {
+        assert!(OutputFormat::parse("xml").is_err());
+    }
+
+    #[test]
+    fn parse_aggregate_accepts_known_values() {
+        assert!(parse_aggregate("off").unwrap().is_none());
+        assert!(matches!(parse_aggregate("packages").unwrap(), Some(intel_rapl::Aggregation::PackagesOnly)));
+        assert!(matches!(parse_aggregate("leaves").unwrap(), Some(intel_rapl::Aggregation::LeavesOnly)));
+        assert!(parse_aggregate("bogus").is_err());
+    }
+
+    #[test]
+    fn parse_domain_list_splits_trims_and_drops_empties() {
+        assert_eq!(parse_domain_list("dram, package-0,, pp0"), vec!["dram", "package-0", "pp0"]);
+        assert_eq!(parse_domain_list(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn parse_cpu_list_splits_and_parses_numbers() {
+        assert_eq!(parse_cpu_list("0,8").unwrap(), vec![0, 8]);
+        assert!(parse_cpu_list("0,nope").is_err());
+    }
+
+    #[test]
+    fn json_escape_escapes_backslash_and_quote() {
+        assert_eq!(json_escape(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+
+    #[test]
+    fn parse_constraint_assignment_splits_zone_from_index_on_the_last_colon() {
+        // Regression test: zone ids can themselves contain a colon (subzone ids are
+        // "<zone>:<subzone>", e.g. "0:1"), so the index must come from the *last* ':'.
+        assert_eq!(parse_constraint_assignment("0:1:0=15000000").unwrap(), ("0:1".to_string(), 0, 15000000));
+        assert_eq!(parse_constraint_assignment("package-0:1=500").unwrap(), ("package-0".to_string(), 1, 500));
+        assert!(parse_constraint_assignment("package-0=500").is_err());
+        assert!(parse_constraint_assignment("package-0:x=500").is_err());
+    }
+
+    #[test]
+    fn parse_enabled_assignment_parses_on_and_off() {
+        assert_eq!(parse_enabled_assignment("package-0=on").unwrap(), ("package-0".to_string(), true));
+        assert_eq!(parse_enabled_assignment("package-0=off").unwrap(), ("package-0".to_string(), false));
+        assert!(parse_enabled_assignment("package-0=maybe").is_err());
+        assert!(parse_enabled_assignment("package-0").is_err());
+    }
 }